@@ -0,0 +1,121 @@
+use directories::ProjectDirs;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+
+use std::{fs, path::PathBuf};
+
+use crate::ErrBox;
+
+const QUALIFIER: &str = "net";
+const ORGANIZATION: &str = "musicforprogramming";
+const APPLICATION: &str = "mfpdl";
+
+/// Persisted user defaults, loaded from `<config dir>/config.toml`. A value present here is
+/// overridden by its corresponding CLI flag, and itself overrides the hardcoded default.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Settings {
+    pub jobs: Option<usize>,
+    pub outdir: Option<PathBuf>,
+    pub tag: Option<bool>,
+    pub playlist: Option<String>,
+}
+
+/// One entry of the download-history manifest: what got fetched, where it landed, and whether
+/// the transfer ran to completion.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub episode_url: String,
+    pub path: PathBuf,
+    pub title: String,
+    pub completed: bool,
+}
+
+/// The download-history manifest, letting an aborted multi-episode run resume cleanly and
+/// `--sync` skip episodes that were already fetched.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    pub fn completed_entry(&self, episode_url: &str) -> Option<&HistoryEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.episode_url == episode_url && entry.completed)
+    }
+
+    /// Insert or update the entry for `episode_url`.
+    pub fn record(&mut self, episode_url: String, path: PathBuf, title: String, completed: bool) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.episode_url == episode_url)
+        {
+            Some(entry) => {
+                entry.path = path;
+                entry.title = title;
+                entry.completed = completed;
+            }
+            None => self.entries.push(HistoryEntry {
+                episode_url,
+                path,
+                title,
+                completed,
+            }),
+        }
+    }
+}
+
+fn project_dirs() -> Result<ProjectDirs, ErrBox> {
+    ProjectDirs::from(QUALIFIER, ORGANIZATION, APPLICATION)
+        .ok_or_else(|| format_err!("Could not determine the platform config directory").into())
+}
+
+pub fn settings_path() -> Result<PathBuf, ErrBox> {
+    Ok(project_dirs()?.config_dir().join("config.toml"))
+}
+
+pub fn history_path() -> Result<PathBuf, ErrBox> {
+    Ok(project_dirs()?.config_dir().join("history.toml"))
+}
+
+pub fn load_settings() -> Result<Settings, ErrBox> {
+    let path = settings_path()?;
+    if !path.exists() {
+        return Ok(Settings::default());
+    }
+
+    let raw = fs::read_to_string(path)?;
+    let settings: Settings = toml::from_str(&raw)?;
+
+    if settings.jobs == Some(0) {
+        return Err(format_err!("Persisted jobs setting cannot be 0").into());
+    }
+
+    Ok(settings)
+}
+
+pub fn load_history() -> Result<History, ErrBox> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(History::default());
+    }
+
+    let raw = fs::read_to_string(path)?;
+    Ok(toml::from_str(&raw)?)
+}
+
+/// Overwrite the history manifest by writing to a temp file in the same directory and renaming
+/// it over the real path, so a run aborted mid-write can't leave a corrupt manifest behind.
+pub fn save_history(history: &History) -> Result<(), ErrBox> {
+    let path = history_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp_path = path.with_extension("toml.tmp");
+    fs::write(&tmp_path, toml::to_string_pretty(history)?)?;
+    fs::rename(&tmp_path, &path)?;
+
+    Ok(())
+}
@@ -0,0 +1,505 @@
+use failure::format_err;
+use futures::{lock::Mutex, prelude::*};
+use id3::{
+    frame::{Comment, Content, Picture, PictureType},
+    Frame, Tag, Version,
+};
+use indicatif::ProgressBar;
+use reqwest::{header::RANGE, Client, Response, StatusCode};
+use tokio::{fs::OpenOptions, prelude::*, sync::Semaphore};
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    config::{self, History},
+    source::{EpisodeMeta, EpisodeRef, Source},
+    ErrBox,
+};
+
+/// A single point of truth for how much a failed scrape or download is retried, and how long to
+/// wait between attempts. The wait doubles with each attempt and is capped at `MAX_BACKOFF`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    fn backoff_for(&self, attempt: u32) -> Duration {
+        let millis = self
+            .backoff
+            .as_millis()
+            .saturating_mul(1u128 << attempt.min(10));
+        Duration::from_millis(millis.min(Self::MAX_BACKOFF.as_millis()) as u64)
+    }
+}
+
+/// Retry `f` up to `retry.max_retries` times (with exponential backoff in between) before giving
+/// up and returning its last error.
+async fn with_retry<T, F, Fut>(retry: &RetryPolicy, mut f: F) -> Result<T, ErrBox>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ErrBox>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(_) if attempt < retry.max_retries => {
+                attempt += 1;
+                tokio::time::delay_for(retry.backoff_for(attempt)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Extracts the total resource size from a response's `Content-Range` header
+/// (`bytes <start>-<end>/<total>` or `bytes */<total>`), falling back to
+/// `Content-Length` when the header is absent (i.e. the server answered a plain `200 OK`).
+fn total_size_of(resp: &Response) -> Option<u64> {
+    resp.headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.rsplit('/').next())
+        .and_then(|total| total.parse::<u64>().ok())
+        .or_else(|| resp.content_length())
+}
+
+/// Streams `resp`'s body into `file`. On a transport error mid-transfer, retries up to
+/// `retry.max_retries` times by re-issuing the GET with a `Range` header covering the bytes
+/// already written, rather than restarting the whole transfer from zero.
+async fn stream_to_file_with_retry(
+    client: &Client,
+    url: &str,
+    file: &mut tokio::fs::File,
+    mut resp: Response,
+    pb: &ProgressBar,
+    retry: &RetryPolicy,
+) -> Result<(), ErrBox> {
+    let mut attempt = 0;
+    loop {
+        let mut stream = resp.bytes_stream().err_into::<ErrBox>();
+        let result: Result<(), ErrBox> = async {
+            while let Some(res) = stream.next().await {
+                let bytes = res?;
+                file.write_all(&bytes).await?;
+                pb.inc(bytes.len() as u64);
+            }
+            Ok(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retry.max_retries => {
+                attempt += 1;
+                pb.println(format!(
+                    "Transfer error for {} ({}), retrying ({}/{}) in {:?}...",
+                    url,
+                    e,
+                    attempt,
+                    retry.max_retries,
+                    retry.backoff_for(attempt)
+                ));
+                tokio::time::delay_for(retry.backoff_for(attempt)).await;
+
+                let written = file.metadata().await?.len();
+                resp = client
+                    .get(url)
+                    .header(RANGE, format!("bytes={}-", written))
+                    .send()
+                    .await?;
+                if resp.status() != StatusCode::PARTIAL_CONTENT {
+                    return Err(format_err!(
+                        "Retry did not resume with 206 Partial Content, got {}",
+                        resp.status()
+                    )
+                    .into());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Downloads the resource at `url` to the specified location while respecting a job count quota
+/// guarded by `sema`. `bars` must contain as many bar entries as there are permits in the
+/// semaphore.
+///
+/// When `resume` is set, a pre-existing `<path>.part` is resumed via a `Range` request rather
+/// than being silently skipped; the file is only moved to its final `path` once its on-disk size
+/// matches the size reported by the server. With `resume` unset, the previous overwrite-skip
+/// behavior is preserved: an existing `path` is left untouched. Transport errors and non-2xx/3xx
+/// statuses are retried per `retry`, both for the initiating request and the transfer itself,
+/// rather than failing the whole download immediately.
+async fn download_with_sema(
+    client: Arc<Client>,
+    url: String,
+    sema: Arc<Semaphore>,
+    bars: Arc<Vec<Mutex<ProgressBar>>>,
+    path: PathBuf,
+    resume: bool,
+    retry: RetryPolicy,
+) -> Result<(), ErrBox> {
+    // Wait for a free progress bar
+    let _permit = sema.acquire().await;
+    let pb = bars
+        .iter()
+        .filter_map(|mutex| mutex.try_lock())
+        .next()
+        .ok_or_else(|| format_err!("Could not acquire a lock for a progress bar despite permit"))?;
+
+    pb.set_message(
+        path.file_name()
+            .ok_or_else(|| format_err!("Could not get file name from path for {:?}", path))?
+            .to_str()
+            .unwrap(),
+    );
+
+    if path.exists() {
+        pb.println(format!("File {:?} already exists, skipping...", path));
+        pb.set_position(0);
+        pb.set_length(0);
+        pb.set_message("Idle");
+        return Ok(());
+    }
+
+    if !resume {
+        let resp = with_retry(&retry, || {
+            let client = &client;
+            let url = &url;
+            let path = &path;
+            async move {
+                let resp = client.get(url).send().await?;
+                if !resp.status().is_success() {
+                    return Err(
+                        format_err!("Unexpected status {} for {:?}", resp.status(), path).into(),
+                    );
+                }
+                Ok(resp)
+            }
+        })
+        .await?;
+        let len = resp
+            .content_length()
+            .ok_or_else(|| format_err!("Could not get Content-Length for {:?}", path))?;
+        pb.set_length(len as u64);
+        pb.set_position(0);
+
+        let mut file = match OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .await
+        {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                pb.println(format!("File {:?} already exists, skipping...", path));
+                pb.set_message("Idle");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        stream_to_file_with_retry(&client, &url, &mut file, resp, &pb, &retry).await?;
+
+        return Ok(());
+    }
+
+    let part_path = {
+        let mut s = path.clone().into_os_string();
+        s.push(".part");
+        PathBuf::from(s)
+    };
+
+    let existing_len = tokio::fs::metadata(&part_path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+
+    let resp = with_retry(&retry, || {
+        let client = &client;
+        let url = &url;
+        let path = &path;
+        async move {
+            let mut req = client.get(url);
+            if existing_len > 0 {
+                req = req.header(RANGE, format!("bytes={}-", existing_len));
+            }
+            let resp = req.send().await?;
+            match resp.status() {
+                StatusCode::PARTIAL_CONTENT | StatusCode::OK | StatusCode::RANGE_NOT_SATISFIABLE => {
+                    Ok(resp)
+                }
+                status => Err(format_err!("Unexpected status {} for {:?}", status, path).into()),
+            }
+        }
+    })
+    .await?;
+
+    let total_len = total_size_of(&resp)
+        .ok_or_else(|| format_err!("Could not determine total size for {:?}", path))?;
+    pb.set_length(total_len);
+
+    match resp.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            pb.set_position(existing_len);
+            let mut file = OpenOptions::new().append(true).open(&part_path).await?;
+            stream_to_file_with_retry(&client, &url, &mut file, resp, &pb, &retry).await?;
+        }
+        StatusCode::OK => {
+            // The server ignored our Range request; restart the file from scratch.
+            pb.set_position(0);
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&part_path)
+                .await?;
+            stream_to_file_with_retry(&client, &url, &mut file, resp, &pb, &retry).await?;
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            // The part file already holds the full content.
+            pb.set_position(total_len);
+        }
+        status => unreachable!("with_retry above only returns one of the three matched statuses, got {}", status),
+    }
+
+    let final_len = tokio::fs::metadata(&part_path).await?.len();
+    if final_len != total_len {
+        return Err(format_err!(
+            "Downloaded size {} for {:?} does not match expected size {}, leaving partial file for later resume",
+            final_len,
+            path,
+            total_len
+        )
+        .into());
+    }
+
+    tokio::fs::rename(&part_path, &path).await?;
+
+    Ok(())
+}
+
+/// Write ID3v2 tags (and cover art, if scraped) to a freshly downloaded episode. Runs as a
+/// post-processing step on the final file path, so it composes with resumed downloads.
+async fn tag_episode(client: &Client, path: &Path, meta: &EpisodeMeta) -> Result<(), ErrBox> {
+    let mut tag = Tag::new();
+    tag.set_title(meta.title.clone());
+    tag.set_album(meta.album.clone());
+    if let Some(artist) = &meta.artist {
+        tag.set_artist(artist.clone());
+    }
+    if let Some(track_no) = meta.track_no {
+        tag.set_track(track_no);
+    }
+    if !meta.tracklist.is_empty() {
+        tag.add_frame(Frame::with_content(
+            "COMM",
+            Content::Comment(Comment {
+                lang: "eng".to_owned(),
+                description: "Tracklist".to_owned(),
+                text: meta.tracklist.join("\n"),
+            }),
+        ));
+    }
+
+    if let Some(cover_url) = &meta.cover_url {
+        let resp = client.get(cover_url).send().await?;
+        let mime_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("image/jpeg")
+            .to_owned();
+        let data = resp.bytes().await?.to_vec();
+        tag.add_frame(Frame::with_content(
+            "APIC",
+            Content::Picture(Picture {
+                mime_type,
+                picture_type: PictureType::CoverFront,
+                description: "Cover".to_owned(),
+                data,
+            }),
+        ));
+    }
+
+    tag.write_to_path(path, Version::Id3v24)?;
+
+    Ok(())
+}
+
+/// Outcome of handling a single episode, carrying enough to place it in an ordered playlist.
+/// `index` is the episode's position on the archive page (`0` for the separately-fetched latest
+/// episode), and `success` is `false` when scraping or downloading failed for it (a tagging
+/// failure alone still counts as a success).
+pub struct DownloadResult {
+    pub index: usize,
+    pub path: PathBuf,
+    pub title: String,
+    pub success: bool,
+}
+
+/// Drives a `Source` end to end: lists episodes, downloads and tags each one within a bounded
+/// job pool, and records successful downloads in the history manifest. This is the part that future sources
+/// (an RSS feed, a mirror host, a local JSON listing) get for free by implementing `Source`.
+pub struct Engine {
+    client: Arc<Client>,
+    sema: Arc<Semaphore>,
+    bars: Arc<Vec<Mutex<ProgressBar>>>,
+    history: Arc<Mutex<History>>,
+    outdir: PathBuf,
+    resume: bool,
+    tag: bool,
+    retry: RetryPolicy,
+    sync: bool,
+}
+
+impl Engine {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        client: Arc<Client>,
+        sema: Arc<Semaphore>,
+        bars: Arc<Vec<Mutex<ProgressBar>>>,
+        history: Arc<Mutex<History>>,
+        outdir: PathBuf,
+        resume: bool,
+        tag: bool,
+        retry: RetryPolicy,
+        sync: bool,
+    ) -> Self {
+        Engine {
+            client,
+            sema,
+            bars,
+            history,
+            outdir,
+            resume,
+            tag,
+            retry,
+            sync,
+        }
+    }
+
+    /// Download (and optionally tag) a single episode, recording a successful download in the
+    /// history manifest as completed; a failed episode is not recorded there. Never propagates an
+    /// error: a failed episode is reported via the `MultiProgress` and comes back as a
+    /// `DownloadResult` with `success: false`, so one dead mirror link doesn't cancel its
+    /// siblings. Tagging runs after the download is complete and is best-effort: a tagging
+    /// failure is logged but still yields `success: true`.
+    async fn process(&self, source: &dyn Source, episode: EpisodeRef) -> DownloadResult {
+        if self.sync {
+            if let Some(entry) = self.history.lock().await.completed_entry(&episode.page_url) {
+                return DownloadResult {
+                    index: episode.index,
+                    path: entry.path.clone(),
+                    title: entry.title.clone(),
+                    success: true,
+                };
+            }
+        }
+
+        let attempt: Result<(PathBuf, EpisodeMeta), ErrBox> = async {
+            let meta =
+                with_retry(&self.retry, || source.resolve_file_url(&self.client, &episode))
+                    .await?;
+            let fname = meta.file_url.split('/').next_back().unwrap();
+            let path = self.outdir.join(fname);
+
+            download_with_sema(
+                self.client.clone(),
+                meta.file_url.clone(),
+                self.sema.clone(),
+                self.bars.clone(),
+                path.clone(),
+                self.resume,
+                self.retry,
+            )
+            .await?;
+
+            Ok((path, meta))
+        }
+        .await;
+
+        match attempt {
+            Ok((path, meta)) => {
+                // Tagging is best-effort post-processing on an already-complete download: a
+                // failure here (a 404'd cover URL, a malformed write) must not turn a correctly
+                // downloaded episode into a permanent failure that `download_with_sema`'s
+                // `path.exists()` skip would then never retry.
+                if self.tag {
+                    if let Err(e) = tag_episode(&self.client, &path, &meta).await {
+                        let msg = format!(
+                            "Episode {} ({}) downloaded but tagging failed: {}",
+                            episode.index, episode.page_url, e
+                        );
+                        match self.bars.iter().filter_map(|mutex| mutex.try_lock()).next() {
+                            Some(pb) => pb.println(msg),
+                            None => println!("{}", msg),
+                        }
+                    }
+                }
+
+                let mut h = self.history.lock().await;
+                h.record(episode.page_url.clone(), path.clone(), meta.title.clone(), true);
+                let _ = config::save_history(&h);
+
+                DownloadResult {
+                    index: episode.index,
+                    path,
+                    title: meta.title,
+                    success: true,
+                }
+            }
+            Err(e) => {
+                let msg = format!(
+                    "Episode {} ({}) failed: {}, omitting from playlist",
+                    episode.index, episode.page_url, e
+                );
+                match self.bars.iter().filter_map(|mutex| mutex.try_lock()).next() {
+                    Some(pb) => pb.println(msg),
+                    None => println!("{}", msg),
+                }
+                DownloadResult {
+                    index: episode.index,
+                    path: PathBuf::new(),
+                    title: String::new(),
+                    success: false,
+                }
+            }
+        }
+    }
+
+    /// Run the full archive pull (or just the latest episode, if `only_latest`) against `source`,
+    /// returning one `DownloadResult` per episode attempted.
+    pub async fn run(
+        &self,
+        source: &dyn Source,
+        only_latest: bool,
+    ) -> Result<Vec<DownloadResult>, ErrBox> {
+        let latest = source.latest_episode(&self.client).await?;
+
+        if only_latest {
+            return Ok(vec![self.process(source, latest).await]);
+        }
+
+        let rest = source.list_episodes(&self.client).await?;
+
+        let latest_fut = self.process(source, latest);
+        let rest_futs = future::join_all(rest.into_iter().map(|episode| self.process(source, episode)));
+
+        let (latest_result, mut rest_results) = future::join(latest_fut, rest_futs).await;
+
+        let mut results = vec![latest_result];
+        results.append(&mut rest_results);
+        Ok(results)
+    }
+}
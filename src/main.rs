@@ -2,101 +2,54 @@ use clap::{App, Arg, ArgMatches};
 use failure::format_err;
 use futures::{lock::Mutex, prelude::*};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use lazy_static::lazy_static;
-use reqwest::Response;
-use scraper::{Html, Selector};
-use tokio::{fs::OpenOptions, prelude::*, sync::Semaphore, task};
-
-use std::{io, iter::Iterator, path::PathBuf, sync::Arc, fs};
-
-const MFP_URL: &'static str = "https://www.musicforprogramming.net";
-const DEFAULT_N_JOBS: &'static str = "8";
-
-// HTML element selectors for the scraper lib, reused across downloads
-lazy_static! {
-    static ref MFP_FILE_SELECTOR: Selector = Selector::parse("div .pad a[href$=mp3]")
-        .map_err(|e| format_err!("Could not parse the file selector: {:?}", e))
-        .unwrap();
-    static ref MFP_EP_SELECTOR: Selector = Selector::parse("#episodes a")
-        .map_err(|e| format_err!("Could not parse the episode selector: {:?}", e))
-        .unwrap();
-}
+use reqwest::Client;
+use tokio::{sync::Semaphore, task};
 
-type ErrBox = Box<dyn std::error::Error>;
+use std::{path::Path, path::PathBuf, sync::Arc, fs, time::Duration};
 
-/// Downloads a `reqwest::Response` to the specified location while respecting a job count quota
-/// guarded by `sema`. `bars` must contain as many bar entries as there are permits in the
-/// semaphore.
-async fn download_with_sema(
-    resp: Response,
-    sema: Arc<Semaphore>,
-    bars: Arc<Vec<Mutex<ProgressBar>>>,
-    path: PathBuf,
-) -> Result<(), ErrBox> {
-    // Wait for a free progress bar
-    let _permit = sema.acquire().await;
-    let pb = bars
-        .iter()
-        .filter_map(|mutex| mutex.try_lock())
-        .nth(0)
-        .ok_or_else(|| format_err!("Could not acquire a lock for a progress bar despite permit"))?;
-
-    // Find out when the progress bar should end
-    let len = resp
-        .content_length()
-        .ok_or_else(|| format_err!("Could not get Content-Length for {:?}", path))?;
-
-    // Prepare the progress bar
-    pb.set_length(len as u64);
-    pb.set_position(0);
-    pb.set_message(
-        path.file_name()
-            .ok_or_else(|| format_err!("Could not get file name from path for {:?}", path))?
-            .to_str()
-            .unwrap(),
-    );
+mod config;
+mod engine;
+mod source;
 
-    let mut file = match OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(&path)
-        .await
-    {
-        Ok(f) => f,
-        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
-            pb.println(format!("File {:?} already exists, skipping...", path));
-            pb.set_message("Idle");
-            return Ok(());
-        }
-        Err(e) => return Err(e.into()),
-    };
+use engine::{DownloadResult, Engine, RetryPolicy};
+use source::{MfpSource, Source};
+
+const DEFAULT_N_JOBS: &str = "8";
 
-    // Stream the response to a file
-    let mut stream = resp.bytes_stream().err_into::<ErrBox>();
+type ErrBox = Box<dyn std::error::Error>;
 
-    while let Some(res) = stream.next().await {
-        let bytes = res?;
-        file.write_all(&bytes).await?;
-        pb.inc(bytes.len() as u64);
+/// Write an extended M3U playlist listing every successfully downloaded episode, in episode
+/// order, with `#EXTINF` durations read back from the MP3 files themselves.
+fn write_playlist(outdir: &Path, playlist_name: &str, results: &[DownloadResult]) -> Result<(), ErrBox> {
+    let mut out = String::from("#EXTM3U\n");
+
+    for result in results {
+        let duration = mp3_duration::from_path(&result.path)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let fname = result
+            .path
+            .file_name()
+            .ok_or_else(|| format_err!("Could not get file name for {:?}", result.path))?
+            .to_str()
+            .unwrap();
+
+        out.push_str(&format!("#EXTINF:{},{}\n", duration, result.title));
+        out.push_str(&format!("{}\n", fname));
     }
 
+    fs::write(outdir.join(playlist_name), out)?;
+
     Ok(())
 }
 
-/// Retrieve a file URL for the specified musicforprogramming.net episode URL
-async fn scrape_episode_file_url(url: &str) -> Result<String, ErrBox> {
-    let resp = reqwest::get(url).await?;
-    let fragment = Html::parse_document(&resp.text().await?);
-
-    let file_url = fragment
-        .select(&*MFP_FILE_SELECTOR)
-        .nth(0)
-        .ok_or_else(|| format_err!("Could not find file URL for {}", url))?
-        .value()
-        .attr("href")
-        .ok_or_else(|| format_err!("Could not find href for file URL element in {}", url))?;
-
-    Ok(file_url.to_owned())
+/// Resolve `--source` into the `Source` implementation it names. Only `mfp` exists today; new
+/// sources (an RSS feed, a mirror host, a local JSON listing) register here.
+fn select_source(name: &str) -> Box<dyn Source> {
+    match name {
+        "mfp" => Box::new(MfpSource),
+        other => unreachable!("unknown source {:?} should have been rejected by the CLI validator", other),
+    }
 }
 
 fn cli_setup<'a>() -> ArgMatches<'a> {
@@ -119,13 +72,12 @@ fn cli_setup<'a>() -> ArgMatches<'a> {
                 .validator(|val| {
                     let v = val
                         .parse::<usize>()
-                        .map_err(|e| format!("Could not parse as number: {}", e.to_string()))?;
+                        .map_err(|e| format!("Could not parse as number: {}", e))?;
                     if v == 0 {
                         return Err("Job count cannot be 0".to_owned());
                     }
                     Ok(())
-                })
-                .default_value(DEFAULT_N_JOBS),
+                }),
         )
         .arg(
             Arg::with_name("outdir")
@@ -137,13 +89,89 @@ fn cli_setup<'a>() -> ArgMatches<'a> {
                 .validator(|path| {
                     let p = path
                         .parse::<PathBuf>()
-                        .map_err(|e| format!("Could not parse as a path: {}", e.to_string()))?;
+                        .map_err(|e| format!("Could not parse as a path: {}", e))?;
                     if p.exists() && p.is_file() {
                         return Err("Existing path must not be a file".to_owned());
                     }
                     Ok(())
+                }),
+        )
+        .arg(
+            Arg::with_name("no-resume")
+                .long("no-resume")
+                .help("Don't resume partially downloaded files, skip them like before instead")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("tag")
+                .long("tag")
+                .help("Write ID3 tags and cover art to downloaded episodes, overriding a persisted no-tag setting")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("no-tag"),
+        )
+        .arg(
+            Arg::with_name("no-tag")
+                .long("no-tag")
+                .help("Don't write ID3 tags and cover art to downloaded episodes")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("playlist")
+                .long("playlist")
+                .help("Write an extended M3U playlist of the whole archive to <outdir>/<name.m3u8>")
+                .takes_value(true)
+                .required(false)
+                .validator(|name| {
+                    if !name.ends_with(".m3u") && !name.ends_with(".m3u8") {
+                        return Err("Playlist name must end in .m3u or .m3u8".to_owned());
+                    }
+                    Ok(())
+                }),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .help("Only download episodes not already recorded as complete in the history manifest")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .help("How many times to retry a failed scrape or download before giving up on it")
+                .takes_value(true)
+                .required(false)
+                .validator(|val| {
+                    val.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|e| format!("Could not parse as a number: {}", e))
                 })
-                .default_value("."),
+                .default_value("3"),
+        )
+        .arg(
+            Arg::with_name("retry-backoff")
+                .long("retry-backoff")
+                .help("Base backoff in milliseconds between retries, doubled on every attempt and capped at 60s")
+                .takes_value(true)
+                .required(false)
+                .validator(|val| {
+                    val.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|e| format!("Could not parse as a number: {}", e))
+                })
+                .default_value("500"),
+        )
+        .arg(
+            Arg::with_name("source")
+                .long("source")
+                .help("Which archive to pull episodes from")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["mfp"])
+                .default_value("mfp"),
         )
         .get_matches()
 }
@@ -152,11 +180,26 @@ fn cli_setup<'a>() -> ArgMatches<'a> {
 async fn main() -> Result<(), ErrBox> {
     let matches = cli_setup();
     // Setup the MultiProgress bar
-    let mpb = MultiProgress::new();
-
-    let n_jobs = matches.value_of("jobs").unwrap().parse()?;
-
-    let outdir = matches.value_of("outdir").unwrap().parse::<PathBuf>()?;
+    let mpb = Arc::new(MultiProgress::new());
+
+    // CLI flags override the persisted config file, which in turn overrides the hardcoded
+    // defaults
+    let settings = config::load_settings()?;
+    let history = Arc::new(Mutex::new(config::load_history()?));
+
+    let n_jobs: usize = matches
+        .value_of("jobs")
+        .map(|v| v.parse())
+        .transpose()?
+        .or(settings.jobs)
+        .unwrap_or_else(|| DEFAULT_N_JOBS.parse().unwrap());
+
+    let outdir = matches
+        .value_of("outdir")
+        .map(|v| v.parse::<PathBuf>())
+        .transpose()?
+        .or_else(|| settings.outdir.clone())
+        .unwrap_or_else(|| PathBuf::from("."));
     fs::create_dir_all(&outdir)?;
 
     // Setup the shared bars lock
@@ -175,73 +218,75 @@ async fn main() -> Result<(), ErrBox> {
     // Setup a semaphore for tracking available bars
     let sema = Arc::new(Semaphore::new(n_jobs));
 
-    // Obtain the main page
-    let resp = reqwest::get(MFP_URL).await?;
-    if !resp.status().is_success() {
-        panic!("Request failed for {}", MFP_URL);
-    }
+    // Threaded through every request so that range headers can be set for resumable downloads
+    let client = Arc::new(Client::new());
 
-    // Scrape latest episode file URL
-    let latest_url = scrape_episode_file_url(MFP_URL).await?;
+    let resume = !matches.is_present("no-resume");
+    let tag = if matches.is_present("tag") {
+        true
+    } else if matches.is_present("no-tag") {
+        false
+    } else {
+        settings.tag.unwrap_or(false)
+    };
+    let playlist_name = matches
+        .value_of("playlist")
+        .map(|v| v.to_owned())
+        .or_else(|| settings.playlist.clone());
+    let sync = matches.is_present("sync");
+
+    let retry = RetryPolicy {
+        max_retries: matches.value_of("retries").unwrap().parse()?,
+        backoff: Duration::from_millis(matches.value_of("retry-backoff").unwrap().parse()?),
+    };
 
-    let latest_fname = latest_url.split("/").last().unwrap();
-    let latest_resp = reqwest::get(&latest_url).await?;
+    let source = select_source(matches.value_of("source").unwrap());
 
-    let latest_fut = download_with_sema(
-        latest_resp,
+    let engine = Engine::new(
+        client.clone(),
         sema.clone(),
         bars.clone(),
-        outdir.join(latest_fname),
+        history.clone(),
+        outdir.clone(),
+        resume,
+        tag,
+        retry,
+        sync,
     );
 
-    // Scrape the rest of the espiode file URLs
-    let body = resp.text().await?;
-    let fragment = Html::parse_document(&body);
-
-    let dl_futs = fragment.select(&*MFP_EP_SELECTOR).map(|episode| {
-        let bars4fut = bars.clone();
-        let sema4fut = sema.clone();
-        let outdir4fut = outdir.clone();
-        async move {
-            let subpage = episode.value().attr("href").unwrap();
-            let ep_url = &format!("{}/{}", MFP_URL, subpage);
-
-            let file_url = scrape_episode_file_url(ep_url).await?;
-            let fname = file_url.split("/").last().unwrap();
-
-            let file_resp = reqwest::get(&file_url).await?;
-
-            download_with_sema(file_resp, sema4fut, bars4fut, outdir4fut.join(fname.to_owned())).await?;
-
-            Result::<(), ErrBox>::Ok(())
-        }
-    });
-
-    let downloads_joined = future::try_join_all(dl_futs).err_into::<ErrBox>();
-
+    let mpb4join = mpb.clone();
     let bar_join_fut = async move {
-        task::spawn_blocking(move || mpb.join_and_clear())
+        task::spawn_blocking(move || mpb4join.join_and_clear())
             .err_into::<ErrBox>()
             .await??;
         Result::<(), ErrBox>::Ok(())
     };
 
-    let cleanup_after_download_fut = async move {
-        if matches.is_present("latest") {
-            latest_fut.await?;
-        } else {
-            future::try_join(latest_fut, downloads_joined).await?;
-        }
+    let run_fut = async {
+        let mut results = engine.run(source.as_ref(), matches.is_present("latest")).await?;
 
         // Required to unblock the MultiProgress bar
         for mutex in bars.iter() {
             mutex.lock().await.finish();
         }
 
+        let failed = results.iter().filter(|r| !r.success).count();
+        println!(
+            "{} episode(s) succeeded, {} failed",
+            results.len() - failed,
+            failed
+        );
+
+        if let Some(playlist_name) = playlist_name {
+            results.sort_by_key(|r| r.index);
+            let successful = results.into_iter().filter(|r| r.success).collect::<Vec<_>>();
+            write_playlist(&outdir, &playlist_name, &successful)?;
+        }
+
         Result::<(), ErrBox>::Ok(())
     };
 
-    future::try_join(cleanup_after_download_fut, bar_join_fut).await?;
+    future::try_join(run_fut, bar_join_fut).await?;
 
     Ok(())
 }
@@ -0,0 +1,190 @@
+use async_trait::async_trait;
+use failure::format_err;
+use lazy_static::lazy_static;
+use reqwest::Client;
+use scraper::{Html, Selector};
+
+use crate::ErrBox;
+
+const MFP_URL: &str = "https://www.musicforprogramming.net";
+const MFP_ALBUM: &str = "musicforprogramming.net";
+
+// HTML element selectors for the scraper lib, reused across episode pages
+lazy_static! {
+    static ref MFP_FILE_SELECTOR: Selector = Selector::parse("div .pad a[href$=mp3]")
+        .map_err(|e| format_err!("Could not parse the file selector: {:?}", e))
+        .unwrap();
+    static ref MFP_EP_SELECTOR: Selector = Selector::parse("#episodes a")
+        .map_err(|e| format_err!("Could not parse the episode selector: {:?}", e))
+        .unwrap();
+    static ref MFP_TITLE_SELECTOR: Selector = Selector::parse("title")
+        .map_err(|e| format_err!("Could not parse the title selector: {:?}", e))
+        .unwrap();
+    static ref MFP_ARTIST_SELECTOR: Selector = Selector::parse("div .pad .artist")
+        .map_err(|e| format_err!("Could not parse the artist selector: {:?}", e))
+        .unwrap();
+    static ref MFP_TRACKLIST_SELECTOR: Selector = Selector::parse("div .pad .tracklist")
+        .map_err(|e| format_err!("Could not parse the tracklist selector: {:?}", e))
+        .unwrap();
+    static ref MFP_COVER_SELECTOR: Selector = Selector::parse("div .pad img")
+        .map_err(|e| format_err!("Could not parse the cover selector: {:?}", e))
+        .unwrap();
+}
+
+/// Everything scraped from an episode page that's needed to both fetch and tag its MP3.
+#[derive(Debug, Clone)]
+pub struct EpisodeMeta {
+    pub file_url: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: String,
+    pub track_no: Option<u32>,
+    pub tracklist: Vec<String>,
+    pub cover_url: Option<String>,
+}
+
+/// A single episode as listed by a `Source`, before its metadata (file URL, title, tags) has
+/// been resolved. `index` is the episode's position in the archive, carried through for playlist
+/// ordering.
+#[derive(Debug, Clone)]
+pub struct EpisodeRef {
+    pub page_url: String,
+    pub index: usize,
+}
+
+/// Where the archive listing and episode metadata come from. `MfpSource` is the only
+/// implementation today, but this is the seam a local JSON feed, an RSS feed, or a mirror host
+/// would plug into without touching the transfer core in `download_with_sema`.
+#[async_trait]
+pub trait Source {
+    /// The latest episode, listed separately from the rest of the archive so it can still be
+    /// fetched when `--latest` skips everything else.
+    async fn latest_episode(&self, client: &Client) -> Result<EpisodeRef, ErrBox>;
+
+    /// Every other episode in the archive, in page order.
+    async fn list_episodes(&self, client: &Client) -> Result<Vec<EpisodeRef>, ErrBox>;
+
+    /// Resolve an `EpisodeRef` into everything needed to download and tag it. Returns the full
+    /// `EpisodeMeta` rather than a bare file URL, since downloaded files get tagged too.
+    async fn resolve_file_url(
+        &self,
+        client: &Client,
+        episode: &EpisodeRef,
+    ) -> Result<EpisodeMeta, ErrBox>;
+}
+
+/// Scrape the file URL and tagging metadata (title, artist, tracklist, cover art) for the
+/// specified musicforprogramming.net episode URL
+async fn scrape_episode_meta(client: &Client, url: &str) -> Result<EpisodeMeta, ErrBox> {
+    let resp = client.get(url).send().await?;
+    let body = resp.text().await?;
+    let fragment = Html::parse_document(&body);
+
+    let file_url = fragment
+        .select(&MFP_FILE_SELECTOR)
+        .next()
+        .ok_or_else(|| format_err!("Could not find file URL for {}", url))?
+        .value()
+        .attr("href")
+        .ok_or_else(|| format_err!("Could not find href for file URL element in {}", url))?
+        .to_owned();
+
+    let title = fragment
+        .select(&MFP_TITLE_SELECTOR)
+        .next()
+        .map(|el| el.text().collect::<String>().trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| {
+            file_url
+                .split('/')
+                .next_back()
+                .unwrap_or("Unknown episode")
+                .to_owned()
+        });
+
+    let artist = fragment
+        .select(&MFP_ARTIST_SELECTOR)
+        .next()
+        .map(|el| el.text().collect::<String>().trim().to_owned())
+        .filter(|s| !s.is_empty());
+
+    let tracklist = fragment
+        .select(&MFP_TRACKLIST_SELECTOR)
+        .flat_map(|el| el.text())
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let cover_url = fragment
+        .select(&MFP_COVER_SELECTOR)
+        .next()
+        .and_then(|el| el.value().attr("src"))
+        .map(|src| {
+            if src.starts_with("http") {
+                src.to_owned()
+            } else {
+                format!("{}/{}", MFP_URL, src.trim_start_matches('/'))
+            }
+        });
+
+    Ok(EpisodeMeta {
+        file_url,
+        title,
+        artist,
+        album: MFP_ALBUM.to_owned(),
+        track_no: parse_track_number(url),
+        tracklist,
+        cover_url,
+    })
+}
+
+/// Parse a track/episode number out of an episode page URL such as
+/// `https://www.musicforprogramming.net/59/`
+fn parse_track_number(url: &str) -> Option<u32> {
+    url.trim_end_matches('/')
+        .rsplit('/')
+        .find_map(|seg| seg.parse::<u32>().ok())
+}
+
+/// The original musicforprogramming.net archive: the homepage always shows the latest episode,
+/// and `#episodes` links to every prior one.
+pub struct MfpSource;
+
+#[async_trait]
+impl Source for MfpSource {
+    async fn latest_episode(&self, _client: &Client) -> Result<EpisodeRef, ErrBox> {
+        Ok(EpisodeRef {
+            page_url: MFP_URL.to_owned(),
+            index: 0,
+        })
+    }
+
+    async fn list_episodes(&self, client: &Client) -> Result<Vec<EpisodeRef>, ErrBox> {
+        let resp = client.get(MFP_URL).send().await?;
+        if !resp.status().is_success() {
+            return Err(format_err!("Request failed for {}", MFP_URL).into());
+        }
+        let body = resp.text().await?;
+        let fragment = Html::parse_document(&body);
+
+        Ok(fragment
+            .select(&MFP_EP_SELECTOR)
+            .enumerate()
+            .map(|(idx, episode)| {
+                let subpage = episode.value().attr("href").unwrap();
+                EpisodeRef {
+                    page_url: format!("{}/{}", MFP_URL, subpage),
+                    index: idx + 1,
+                }
+            })
+            .collect())
+    }
+
+    async fn resolve_file_url(
+        &self,
+        client: &Client,
+        episode: &EpisodeRef,
+    ) -> Result<EpisodeMeta, ErrBox> {
+        scrape_episode_meta(client, &episode.page_url).await
+    }
+}